@@ -1,7 +1,12 @@
 use derive_more::From;
-use std::{env, net::UdpSocket};
-
-use bytes::{BufMut, BytesMut};
+use std::{
+    collections::HashMap,
+    env,
+    io::{Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, TcpListener, TcpStream, UdpSocket},
+    thread,
+    time::Duration,
+};
 
 pub type Result<T> = core::result::Result<T, Error>;
 
@@ -112,6 +117,171 @@ impl From<&DnsMessageHeader> for [u8; 12] {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QType {
+    A,
+    Ns,
+    Cname,
+    Mx,
+    Txt,
+    Aaaa,
+}
+
+impl TryFrom<u16> for QType {
+    type Error = Error;
+
+    fn try_from(value: u16) -> Result<Self> {
+        match value {
+            1 => Ok(QType::A),
+            2 => Ok(QType::Ns),
+            5 => Ok(QType::Cname),
+            15 => Ok(QType::Mx),
+            16 => Ok(QType::Txt),
+            28 => Ok(QType::Aaaa),
+            _ => Err(Error::InvalidQuestion),
+        }
+    }
+}
+
+impl From<QType> for u16 {
+    fn from(qtype: QType) -> Self {
+        match qtype {
+            QType::A => 1,
+            QType::Ns => 2,
+            QType::Cname => 5,
+            QType::Mx => 15,
+            QType::Txt => 16,
+            QType::Aaaa => 28,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QClass {
+    Internet,
+    Chaos,
+    Hesiod,
+}
+
+impl TryFrom<u16> for QClass {
+    type Error = Error;
+
+    fn try_from(value: u16) -> Result<Self> {
+        match value {
+            1 => Ok(QClass::Internet),
+            3 => Ok(QClass::Chaos),
+            4 => Ok(QClass::Hesiod),
+            _ => Err(Error::InvalidQuestion),
+        }
+    }
+}
+
+impl From<QClass> for u16 {
+    fn from(class: QClass) -> Self {
+        match class {
+            QClass::Internet => 1,
+            QClass::Chaos => 3,
+            QClass::Hesiod => 4,
+        }
+    }
+}
+
+/// The record-type specific payload that trails a resource record. Each
+/// concrete variant knows how to serialize itself into wire format so the
+/// response encoder never has to special-case a type.
+trait RData: core::fmt::Debug {
+    fn to_bytes(&self) -> Vec<u8>;
+}
+
+#[derive(Debug)]
+struct ARdata(Ipv4Addr);
+
+impl RData for ARdata {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.octets().to_vec()
+    }
+}
+
+#[derive(Debug)]
+struct AaaaRdata(Ipv6Addr);
+
+impl RData for AaaaRdata {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.octets().to_vec()
+    }
+}
+
+#[derive(Debug)]
+struct CnameRdata(Vec<u8>);
+
+impl RData for CnameRdata {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
+#[derive(Debug)]
+struct TxtRdata(Vec<String>);
+
+impl RData for TxtRdata {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for txt in &self.0 {
+            let bytes = txt.as_bytes();
+            buf.push(bytes.len() as u8);
+            buf.extend_from_slice(bytes);
+        }
+        buf
+    }
+}
+
+#[derive(Debug)]
+struct RawRData(Vec<u8>);
+
+impl RData for RawRData {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
+/// Write a (fully expanded) domain name into `buf`, compressing it against
+/// names seen earlier in the same message. `offset` is the absolute byte
+/// position — counted from byte 0 of the datagram — at which this name will
+/// start, and `pointers` maps each label suffix already emitted to its own
+/// absolute offset. For every suffix of the name (the whole name, then the
+/// name minus its first label, and so on) we either emit a two-byte pointer
+/// to an earlier copy or record the suffix for later reuse.
+fn encode_name(name: &[u8], offset: usize, pointers: &mut HashMap<Vec<u8>, u16>, buf: &mut Vec<u8>) {
+    let mut index = 0;
+    loop {
+        match name.get(index) {
+            None | Some(0) => {
+                buf.push(0);
+                break;
+            }
+            Some(&len) => {
+                let suffix = name[index..].to_vec();
+
+                if let Some(&ptr) = pointers.get(&suffix) {
+                    buf.extend_from_slice(&(0xC000 | ptr).to_be_bytes());
+                    return;
+                }
+
+                // Only 14 bits are available for a pointer target, so names
+                // beyond 0x3FFF can still be written but never referenced.
+                let abs = offset + index;
+                if abs < 0x3FFF {
+                    pointers.insert(suffix, abs as u16);
+                }
+
+                let end = index + 1 + len as usize;
+                buf.extend_from_slice(&name[index..end]);
+                index = end;
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 struct DnsMessageQuestion {
     name: Vec<u8>,
@@ -119,6 +289,18 @@ struct DnsMessageQuestion {
     class: u16,
 }
 
+impl DnsMessageQuestion {
+    fn encode(&self, offset: usize, pointers: &mut HashMap<Vec<u8>, u16>) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_name(&self.name, offset, pointers, &mut buf);
+
+        buf.extend_from_slice(&self.qtype.to_be_bytes());
+        buf.extend_from_slice(&self.class.to_be_bytes());
+
+        buf
+    }
+}
+
 impl From<&DnsMessageQuestion> for Vec<u8> {
     fn from(question: &DnsMessageQuestion) -> Self {
         let mut buf: Vec<u8> = Vec::from(question.name.as_slice());
@@ -136,76 +318,137 @@ struct DnsMessageResponse {
     qtype: u16,
     class: u16,
     ttl: u32,
-    length: u16,
-    data: Vec<u8>,
+    data: Box<dyn RData>,
+}
+
+impl DnsMessageResponse {
+    fn encode(&self, offset: usize, pointers: &mut HashMap<Vec<u8>, u16>) -> Vec<u8> {
+        let rdata = self.data.to_bytes();
+
+        let mut buf = Vec::new();
+        encode_name(&self.name, offset, pointers, &mut buf);
+
+        buf.extend_from_slice(&self.qtype.to_be_bytes());
+        buf.extend_from_slice(&self.class.to_be_bytes());
+        buf.extend_from_slice(&self.ttl.to_be_bytes());
+        buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&rdata);
+
+        buf
+    }
 }
 
 impl From<&DnsMessageResponse> for Vec<u8> {
     fn from(response: &DnsMessageResponse) -> Self {
+        let rdata = response.data.to_bytes();
+
         let mut buf: Vec<u8> = Vec::from(response.name.as_slice());
 
         buf.extend_from_slice(&response.qtype.to_be_bytes());
         buf.extend_from_slice(&response.class.to_be_bytes());
         buf.extend_from_slice(&response.ttl.to_be_bytes());
-        buf.extend_from_slice(&response.length.to_be_bytes());
-        buf.extend_from_slice(&response.data[..]);
+        buf.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&rdata);
 
         buf
     }
 }
 
+// The maximum number of compression jumps we follow before declaring the
+// name malformed. A single name can only legitimately chain a handful of
+// pointers; anything past this is a pointer loop or an attack.
+const MAX_JUMPS: usize = 128;
+
+/// Expand a (possibly compressed) domain name starting at the absolute offset
+/// `l_index` into the whole datagram `data`. Compression pointers are always
+/// absolute from byte 0, so resolving them against a single consistent origin
+/// keeps the math simple. Returns the expanded labels plus the number of bytes
+/// consumed in the *non-pointer* direction, i.e. from `l_index` up to and
+/// including the terminating zero byte or the two bytes of the first pointer.
+fn parse_labels(data: &[u8], l_index: usize) -> Result<(Vec<u8>, usize)> {
+    fn is_valid_compression_pointer(byte: u8) -> bool {
+        byte >> 6 & 0b0000_0011 == 0b0000_0011
+    }
+
+    let mut labels = Vec::new();
+    let mut index = l_index;
+    let mut jumps = 0usize;
+    // Bytes consumed in the forward direction, frozen at the first
+    // pointer we follow (everything after a jump is read "elsewhere").
+    let mut consumed: Option<usize> = None;
+
+    loop {
+        if index >= data.len() {
+            return Err(Error::InvalidQuestion);
+        }
+
+        match data[index] {
+            0 => {
+                labels.push(b'\0');
+                if consumed.is_none() {
+                    consumed = Some(index + 1 - l_index);
+                }
+                break;
+            }
+            len @ 1..=63 => {
+                let end = index + 1 + len as usize;
+                if end > data.len() {
+                    return Err(Error::InvalidQuestion);
+                }
+                labels.extend_from_slice(&data[index..end]);
+                index = end;
+            }
+            pointer if is_valid_compression_pointer(pointer) => {
+                if index + 1 >= data.len() {
+                    return Err(Error::InvalidQuestion);
+                }
+
+                jumps += 1;
+                if jumps > MAX_JUMPS {
+                    return Err(Error::InvalidQuestion);
+                }
+
+                let mut offset = u16::from_be_bytes([pointer, data[index + 1]]);
+                offset &= !(0b11 << 14); // zero out leftmost 2 bits
+                let target = offset as usize;
+
+                // A pointer must only ever jump backward relative to
+                // the byte currently being read; forward or self jumps
+                // are the loops this guard exists to reject.
+                if target >= index {
+                    return Err(Error::InvalidQuestion);
+                }
+
+                if consumed.is_none() {
+                    consumed = Some(index + 2 - l_index);
+                }
+
+                index = target;
+            }
+            _ => return Err(Error::InvalidQuestion),
+        }
+    }
+
+    Ok((labels, consumed.unwrap_or(0)))
+}
+
 fn dns_questions_from_bytes(
     data: &[u8],
     size: usize,
+    base: usize,
     nr_of_questions: &u16,
-) -> Result<Vec<DnsMessageQuestion>> {
+) -> Result<(Vec<DnsMessageQuestion>, usize)> {
     // todo retun number of bytes read
     fn parse_question(
         data: &[u8],
         size: &usize,
         q_index: usize,
     ) -> Result<(DnsMessageQuestion, usize)> {
-        // todo return number of bytes read
-        fn parse_labels(data: &[u8], l_index: usize) -> Result<(Vec<u8>, usize)> {
-            fn is_valid_compression_pointer(byte: &u8) -> bool {
-                byte >> 6 & 0b0000_0011 == 0b0000_0011
-            }
-
-            let mut labels = Vec::new();
-            let mut index = l_index;
-            loop {
-                match &data[index] {
-                    0 => {
-                        labels.push(b'\0');
-                        break;
-                    }
-                    len @ 1..=63 => {
-                        labels.extend_from_slice(&data[index..index + *len as usize + 1]);
-                        index += *len as usize + 1;
-                    }
-                    pointer if is_valid_compression_pointer(pointer) => {
-                        let mut offset = u16::from_be_bytes([*pointer, data[index + 1]]);
-                        offset &= !(0b11 << 14); // zero out leftmost 2 bits
-
-                        let (compressed_labels, _) = parse_labels(data, offset as usize - 12)?; // -12 because of headers are 12 bytes
-
-                        index += 1;
-
-                        labels.extend(compressed_labels);
-                        break;
-                    }
-                    _ => return Err(Error::InvalidQuestion),
-                }
-            }
-
-            Ok((labels, index + 1 - l_index))
-        }
-
-        if data[q_index] == 0 {
+        if q_index >= *size {
             return Err(Error::InvalidQuestion);
         }
 
-        if *size < q_index + data[q_index] as usize + 1 {
+        if data[q_index] == 0 {
             return Err(Error::InvalidQuestion);
         }
 
@@ -238,59 +481,123 @@ fn dns_questions_from_bytes(
     }
 
     let mut questions: Vec<DnsMessageQuestion> = Vec::new();
-    let mut index = 0;
+    let mut index = base;
     for _ in 0..*nr_of_questions {
         let (question, size_in_bytes) = parse_question(data, &size, index)?;
         index += size_in_bytes;
         questions.push(question);
     }
 
-    Ok(questions)
+    Ok((questions, index))
 }
 
-fn dns_response_from_bytes(data: &[u8]) -> Result<DnsMessageResponse> {
-    if data[0] == 0 {
-        return Err(Error::InvalidResponse);
-    }
+/// Build the typed [`RData`] for a record given its wire `qtype` and the raw
+/// rdata bytes, falling back to [`RawRData`] for anything we don't model.
+fn rdata_from(qtype: u16, rdata_bytes: &[u8]) -> Result<Box<dyn RData>> {
+    let data: Box<dyn RData> = match QType::try_from(qtype) {
+        Ok(QType::A) if rdata_bytes.len() == 4 => Box::new(ARdata(Ipv4Addr::from([
+            rdata_bytes[0],
+            rdata_bytes[1],
+            rdata_bytes[2],
+            rdata_bytes[3],
+        ]))),
+        Ok(QType::Aaaa) if rdata_bytes.len() == 16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(rdata_bytes);
+            Box::new(AaaaRdata(Ipv6Addr::from(octets)))
+        }
+        Ok(QType::Cname) => Box::new(CnameRdata(rdata_bytes.to_vec())),
+        Ok(QType::Txt) => {
+            let mut txts = Vec::new();
+            let mut idx = 0;
+            while idx < rdata_bytes.len() {
+                let len = rdata_bytes[idx] as usize;
+                idx += 1;
+                if idx + len > rdata_bytes.len() {
+                    return Err(Error::InvalidResponse);
+                }
+                txts.push(String::from_utf8_lossy(&rdata_bytes[idx..idx + len]).into_owned());
+                idx += len;
+            }
+            Box::new(TxtRdata(txts))
+        }
+        _ => Box::new(RawRData(rdata_bytes.to_vec())),
+    };
 
-    let mut name: Vec<u8> = data
-        .iter()
-        .take_while(|b| **b != b'\0')
-        .map(|b| b.to_owned())
-        .collect();
+    Ok(data)
+}
+
+/// Wire type of the EDNS0 OPT pseudo-record.
+const OPT_RECORD_TYPE: u16 = 41;
+/// The max UDP payload size we advertise in our own OPT pseudo-record. 4096
+/// mirrors the default most real resolvers announce.
+const EDNS_MAX_UDP_PAYLOAD_SIZE: u16 = 4096;
+const MIN_UDP_PAYLOAD_SIZE: usize = 512;
+
+/// Parse the resource records that follow the question section (the answer,
+/// authority and additional sections share the same wire layout). `r_index` is
+/// the absolute offset of the first record; returns the decoded records and
+/// the offset just past the last one.
+fn dns_records_from_bytes(
+    data: &[u8],
+    size: usize,
+    r_index: usize,
+    count: u16,
+) -> Result<(Vec<DnsMessageResponse>, usize)> {
+    fn parse_record(data: &[u8], size: usize, r_index: usize) -> Result<(DnsMessageResponse, usize)> {
+        let (name, name_size) = parse_labels(data, r_index)?;
+
+        let fixed = r_index + name_size;
+        if fixed + 10 > size {
+            return Err(Error::InvalidResponse);
+        }
 
-    name.push(b'\0');
+        let qtype = u16::from_be_bytes([data[fixed], data[fixed + 1]]);
+        let class = u16::from_be_bytes([data[fixed + 2], data[fixed + 3]]);
+        let ttl = u32::from_be_bytes([data[fixed + 4], data[fixed + 5], data[fixed + 6], data[fixed + 7]]);
+        let length = u16::from_be_bytes([data[fixed + 8], data[fixed + 9]]) as usize;
 
-    if name.len() + 10 > data.len() {
-        return Err(Error::InvalidResponse);
-    }
+        let rdata_start = fixed + 10;
+        if rdata_start + length > size {
+            return Err(Error::InvalidResponse);
+        }
 
-    let qtype = u16::from_be_bytes([data[name.len()], data[name.len() + 1]]);
-    let class = u16::from_be_bytes([data[name.len() + 2], data[name.len() + 3]]);
+        let rdata = rdata_from(qtype, &data[rdata_start..rdata_start + length])?;
 
-    let ttl = u32::from_be_bytes([
-        data[name.len() + 4],
-        data[name.len() + 5],
-        data[name.len() + 6],
-        data[name.len() + 7],
-    ]);
-    let length = u16::from_be_bytes([data[name.len() + 8], data[name.len() + 9]]);
+        Ok((
+            DnsMessageResponse {
+                name,
+                qtype,
+                class,
+                ttl,
+                data: rdata,
+            },
+            name_size + 10 + length,
+        ))
+    }
 
-    if name.len() + 10 + length as usize > data.len() {
-        return Err(Error::InvalidResponse);
+    let mut records = Vec::with_capacity(count as usize);
+    let mut index = r_index;
+    for _ in 0..count {
+        let (record, record_size) = parse_record(data, size, index)?;
+        index += record_size;
+        records.push(record);
     }
 
-    let data_start_idx = name.len() + 10;
-    let data = Vec::from(&data[data_start_idx..data_start_idx + length as usize]);
+    Ok((records, index))
+}
 
-    Ok(DnsMessageResponse {
-        name,
-        qtype,
-        class,
-        ttl,
-        length,
-        data,
-    })
+/// Build the OPT pseudo-record we echo back to an EDNS0-capable client. Its
+/// name is the root (a lone zero byte), CLASS carries our max UDP payload
+/// size, and TTL packs the extended RCODE (0) and EDNS version (0).
+fn opt_record() -> DnsMessageResponse {
+    DnsMessageResponse {
+        name: vec![0],
+        qtype: OPT_RECORD_TYPE,
+        class: EDNS_MAX_UDP_PAYLOAD_SIZE,
+        ttl: 0,
+        data: Box::new(RawRData(Vec::new())),
+    }
 }
 
 fn resolve_questions(
@@ -298,34 +605,366 @@ fn resolve_questions(
     header: &DnsMessageHeader,
     questions: &[DnsMessageQuestion],
 ) -> Result<Vec<DnsMessageResponse>> {
-    let mut answers: Vec<DnsMessageResponse> = Vec::with_capacity(questions.len());
+    // Each question is forwarded as its own single-question query, all fired
+    // off before we start collecting replies so a slow upstream answer never
+    // blocks the others. Replies are matched back to queries by the DNS header
+    // `id`; a mismatched or late datagram is discarded. If an answer is still
+    // missing when the read timeout expires we resend the outstanding queries,
+    // up to RETRIES times; if one is still missing after that, this fails.
+    const RETRIES: usize = 2;
+    const TIMEOUT: Duration = Duration::from_secs(2);
+
+    resolver_socket.set_read_timeout(Some(TIMEOUT))?;
+
+    // Per-question outbound query bytes, the length of the question section we
+    // echoed (so we know where the answer section starts), and the id we
+    // stamped so we can match the reply. `by_id` maps that id back to the
+    // question index.
+    let mut query_bytes: Vec<Vec<u8>> = Vec::with_capacity(questions.len());
+    let mut question_lens: Vec<usize> = Vec::with_capacity(questions.len());
+    let mut by_id: HashMap<u16, usize> = HashMap::with_capacity(questions.len());
+
+    for (i, q) in questions.iter().enumerate() {
+        let id = header.id.wrapping_add(i as u16);
+        by_id.insert(id, i);
+
+        let mut query_header = header.clone();
+        query_header.id = id;
+        query_header.qd_count = 1;
+        query_header.an_count = 0;
+        query_header.ns_count = 0;
+        query_header.ar_count = 0;
+
+        let query_header_bytes: [u8; 12] = (&query_header).into();
+        let question_bytes = Vec::from(q);
+
+        let mut query = Vec::with_capacity(12 + question_bytes.len());
+        query.extend_from_slice(&query_header_bytes);
+        query.extend_from_slice(&question_bytes);
+
+        question_lens.push(question_bytes.len());
+        query_bytes.push(query);
+    }
+
+    let mut answers: Vec<Option<DnsMessageResponse>> =
+        (0..questions.len()).map(|_| None).collect();
+
+    for _ in 0..=RETRIES {
+        let outstanding: Vec<usize> = answers
+            .iter()
+            .enumerate()
+            .filter(|(_, a)| a.is_none())
+            .map(|(i, _)| i)
+            .collect();
+
+        if outstanding.is_empty() {
+            break;
+        }
 
-    let mut resolver_req_header = header.clone();
-    resolver_req_header.qd_count = 1;
+        for &i in &outstanding {
+            resolver_socket.send(&query_bytes[i])?;
+        }
 
-    let resolver_req_header_bytes: [u8; 12] = (&resolver_req_header).into();
+        loop {
+            let mut resolver_buf = [0; 512];
 
-    for q in questions.iter() {
-        let resolver_question_bytes = Vec::from(q);
+            let size = match resolver_socket.recv_from(&mut resolver_buf) {
+                Ok((size, _)) => size,
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::WouldBlock
+                        || e.kind() == std::io::ErrorKind::TimedOut =>
+                {
+                    break;
+                }
+                Err(e) => return Err(e.into()),
+            };
 
-        let mut resolver_req_bytes = BytesMut::with_capacity(12 + resolver_question_bytes.len());
-        resolver_req_bytes.put_slice(&resolver_req_header_bytes);
-        resolver_req_bytes.put_slice(&resolver_question_bytes);
+            if size < 12 {
+                continue;
+            }
 
-        let resolver_req_bytess = resolver_req_bytes.freeze();
+            let id = u16::from_be_bytes([resolver_buf[0], resolver_buf[1]]);
 
-        resolver_socket.send(&resolver_req_bytess[..])?;
+            let Some(&idx) = by_id.get(&id) else {
+                continue;
+            };
 
-        let mut resolver_buf = [0; 512];
+            if answers[idx].is_some() {
+                continue;
+            }
 
-        let (_, _) = resolver_socket.recv_from(&mut resolver_buf)?;
+            let answer_start = 12 + question_lens[idx];
+            if size <= answer_start {
+                continue;
+            }
 
-        let response =
-            dns_response_from_bytes(&resolver_buf[12 + resolver_question_bytes.len()..])?;
+            // Parse the first answer record against the whole datagram so a
+            // compressed answer name resolves correctly; an empty or malformed
+            // answer section yields an error we simply discard rather than
+            // indexing past the slice.
+            if let Ok((records, _)) = dns_records_from_bytes(&resolver_buf, size, answer_start, 1) {
+                if let Some(response) = records.into_iter().next() {
+                    answers[idx] = Some(response);
+                }
+            }
 
-        answers.push(response);
+            if answers.iter().all(Option::is_some) {
+                break;
+            }
+        }
+    }
+
+    answers
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .ok_or(Error::InvalidResponse)
+}
+
+/// Decode an (unpadded) base32 string back into its raw bytes. Padding `=` is
+/// tolerated and leftover bits are discarded, matching our padless encoder.
+fn base32_decode(input: &[u8]) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(input.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0u32;
+
+    for &c in input {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a',
+            b'2'..=b'7' => c - b'2' + 26,
+            b'=' => continue,
+            _ => return Err(Error::InvalidQuestion),
+        } as u64;
+
+        buffer = (buffer << 5) | value;
+        bits += 5;
+
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
     }
-    Ok(answers)
+
+    Ok(out)
+}
+
+/// Split a wire-format name into its individual label payloads (length bytes
+/// stripped), stopping at the root label. Returns `None` for a malformed or
+/// compressed name — tunnel names are always written out in full.
+fn split_labels(name: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut labels = Vec::new();
+    let mut index = 0;
+
+    while index < name.len() {
+        let len = name[index] as usize;
+        if len == 0 {
+            return Some(labels);
+        }
+        if len > 63 || index + 1 + len > name.len() {
+            return None;
+        }
+        labels.push(name[index + 1..index + 1 + len].to_vec());
+        index += 1 + len;
+    }
+
+    None
+}
+
+/// If `name` is a tunnel query for `base_domain`, strip the base, reassemble
+/// the data labels and base32-decode them into the carried payload. The first
+/// label is the sequence number; the labels between it and the base domain are
+/// the payload chunks.
+fn decode_tunnel_question(name: &[u8], base_domain: &str) -> Option<(u8, Vec<u8>)> {
+    let labels = split_labels(name)?;
+    let base_labels: Vec<&[u8]> = base_domain.split('.').map(str::as_bytes).collect();
+
+    if labels.len() < base_labels.len() + 1 {
+        return None;
+    }
+
+    let split_at = labels.len() - base_labels.len();
+    for (base, label) in base_labels.iter().zip(&labels[split_at..]) {
+        if !label.eq_ignore_ascii_case(base) {
+            return None;
+        }
+    }
+
+    let seq = *base32_decode(&labels[0]).ok()?.first()?;
+
+    let mut encoded = Vec::new();
+    for label in &labels[1..split_at] {
+        encoded.extend_from_slice(label);
+    }
+
+    let payload = base32_decode(&encoded).ok()?;
+
+    Some((seq, payload))
+}
+
+/// Acknowledge a received tunnel chunk with a TXT answer carrying the next
+/// sequence number the receiver expects, so the sender can drive an ordered
+/// stream.
+fn tunnel_ack(question: &DnsMessageQuestion, next_seq: u8) -> DnsMessageResponse {
+    DnsMessageResponse {
+        name: question.name.clone(),
+        qtype: u16::from(QType::Txt),
+        class: u16::from(QClass::Internet),
+        ttl: 0,
+        data: Box::new(TxtRdata(vec![next_seq.to_string()])),
+    }
+}
+
+/// Transport-agnostic request handler shared by the UDP and TCP listeners.
+/// Parses `request`, optionally forwards the questions to `resolver_addr`, and
+/// returns the fully assembled response datagram. When `truncate` is set (the
+/// UDP path) a response that would exceed the negotiated payload size has its
+/// answer section dropped and the TC bit set so the client retries over TCP;
+/// the TCP path passes `truncate = false` because it is not datagram-bound.
+fn handle_message(
+    request: &[u8],
+    resolver_addr: Option<&str>,
+    tunnel_base: Option<&str>,
+    truncate: bool,
+) -> Result<Vec<u8>> {
+    let mut request_header = DnsMessageHeader::try_from(&request[0..request.len().min(12)])?;
+
+    let size = request.len();
+    let (questions, questions_end) =
+        dns_questions_from_bytes(request, size, 12, &request_header.qd_count)?;
+
+    // EDNS0: an OPT pseudo-record in the additional section advertises the
+    // client's UDP payload size. The answer and authority sections of a query
+    // are empty, so the additional section starts right after the questions.
+    let client_payload_size =
+        dns_records_from_bytes(request, size, questions_end, request_header.ar_count)
+            .ok()
+            .and_then(|(records, _)| {
+                records
+                    .iter()
+                    .find(|r| r.qtype == OPT_RECORD_TYPE)
+                    .map(|opt| opt.class as usize)
+            });
+
+    // Negotiated size is the smaller of what the client can accept and what we
+    // advertise, but never below 512.
+    let negotiated_size = client_payload_size
+        .map(|c| c.min(EDNS_MAX_UDP_PAYLOAD_SIZE as usize))
+        .unwrap_or(MIN_UDP_PAYLOAD_SIZE)
+        .max(MIN_UDP_PAYLOAD_SIZE);
+
+    // A question addressed to the tunnel base domain is handled locally: we
+    // reconstruct its payload and answer with a TXT acknowledgement instead of
+    // forwarding it upstream.
+    let tunnel_answers: Option<Vec<DnsMessageResponse>> = tunnel_base.and_then(|base| {
+        let acks: Vec<DnsMessageResponse> = questions
+            .iter()
+            .filter_map(|q| {
+                decode_tunnel_question(&q.name, base)
+                    .map(|(seq, _payload)| tunnel_ack(q, seq.wrapping_add(1)))
+            })
+            .collect();
+
+        if acks.is_empty() {
+            None
+        } else {
+            Some(acks)
+        }
+    });
+
+    let answers: Vec<DnsMessageResponse> = if let Some(acks) = tunnel_answers {
+        acks
+    } else if let Some(addr) = resolver_addr {
+        let resolver_socket = UdpSocket::bind("0.0.0.0:0")?;
+        resolver_socket.connect(addr)?;
+        // A resolver failure degrades to an answerless response rather than
+        // dropping the query on the floor.
+        resolve_questions(&resolver_socket, &request_header, &questions).unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+
+    request_header.qr = true;
+    request_header.qd_count = questions.len() as u16;
+    request_header.rcode = if request_header.op_code == 0 { 0 } else { 4 };
+
+    let mut pointers: HashMap<Vec<u8>, u16> = HashMap::new();
+    let mut offset = 12;
+
+    let mut response_question_bytes: Vec<u8> = Vec::new();
+    for q in &questions {
+        let encoded = q.encode(offset, &mut pointers);
+        offset += encoded.len();
+        response_question_bytes.extend_from_slice(&encoded);
+    }
+
+    let mut response_answer_bytes: Vec<u8> = Vec::new();
+    for a in &answers {
+        let encoded = a.encode(offset, &mut pointers);
+        offset += encoded.len();
+        response_answer_bytes.extend_from_slice(&encoded);
+    }
+
+    // Echo an OPT record iff the client offered one. Its root name never
+    // compresses, so a throwaway map and offset are fine.
+    let response_additional_bytes: Vec<u8> = if client_payload_size.is_some() {
+        opt_record().encode(offset, &mut HashMap::new())
+    } else {
+        Vec::new()
+    };
+
+    let total = 12
+        + response_question_bytes.len()
+        + response_answer_bytes.len()
+        + response_additional_bytes.len();
+
+    if truncate && total > negotiated_size {
+        request_header.tc = true;
+        response_answer_bytes.clear();
+        request_header.an_count = 0;
+    } else {
+        request_header.tc = false;
+        request_header.an_count = answers.len() as u16;
+    }
+
+    request_header.ar_count = if response_additional_bytes.is_empty() { 0 } else { 1 };
+
+    let response_header_bytes: [u8; 12] = (&request_header).into();
+
+    let mut response_bytes = Vec::with_capacity(total);
+    response_bytes.extend_from_slice(&response_header_bytes);
+    response_bytes.extend_from_slice(&response_question_bytes);
+    response_bytes.extend_from_slice(&response_answer_bytes);
+    response_bytes.extend_from_slice(&response_additional_bytes);
+
+    Ok(response_bytes)
+}
+
+/// Serve DNS over TCP: each message is framed by a two-byte big-endian length
+/// prefix, and a single connection may carry several messages back to back.
+fn handle_tcp_connection(
+    stream: &mut TcpStream,
+    resolver_addr: Option<&str>,
+    tunnel_base: Option<&str>,
+) -> Result<()> {
+    loop {
+        let mut length_prefix = [0u8; 2];
+        match stream.read_exact(&mut length_prefix) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+
+        let length = u16::from_be_bytes(length_prefix) as usize;
+        let mut request = vec![0u8; length];
+        stream.read_exact(&mut request)?;
+
+        let response = handle_message(&request, resolver_addr, tunnel_base, false)?;
+
+        stream.write_all(&(response.len() as u16).to_be_bytes())?;
+        stream.write_all(&response)?;
+    }
+
+    Ok(())
 }
 
 fn main() {
@@ -334,16 +973,37 @@ fn main() {
 
     // Uncomment this block to pass the first stage
     let udp_socket = UdpSocket::bind("127.0.0.1:2053").expect("Failed to bind to address");
+    let tcp_listener = TcpListener::bind("127.0.0.1:2053").expect("Failed to bind to address");
     let mut buf = [0; 512];
 
     let args: Vec<String> = env::args().collect();
-
-    let maybe_resolver_socket = parse_args(&args).map(|r_attr| {
-        let socket = UdpSocket::bind("0.0.0.0:0").expect("Udp client address bind failed");
-        socket
-            .connect(r_attr)
-            .expect("failed to connect to resolver");
-        socket
+    let (resolver, tunnel) = parse_args(&args);
+    let resolver_addr: Option<String> = resolver.map(ToOwned::to_owned);
+    let tunnel_base: Option<String> = tunnel.map(ToOwned::to_owned);
+
+    // TCP runs alongside UDP so clients that hit the TC bit can retry over a
+    // stream transport that is not bound to a single datagram.
+    let tcp_resolver_addr = resolver_addr.clone();
+    let tcp_tunnel_base = tunnel_base.clone();
+    thread::spawn(move || {
+        for stream in tcp_listener.incoming() {
+            match stream {
+                Ok(mut stream) => {
+                    let resolver_addr = tcp_resolver_addr.clone();
+                    let tunnel_base = tcp_tunnel_base.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = handle_tcp_connection(
+                            &mut stream,
+                            resolver_addr.as_deref(),
+                            tunnel_base.as_deref(),
+                        ) {
+                            eprintln!("TCP connection error: {}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("TCP accept error: {}", e),
+            }
+        }
     });
 
     loop {
@@ -351,87 +1011,18 @@ fn main() {
             Ok((size, source)) => {
                 println!("Received {} bytes from {}", size, source);
 
-                if let Ok(mut request_header) = DnsMessageHeader::try_from(&buf[0..12]) {
-                    if let Ok(questions) =
-                        dns_questions_from_bytes(&buf[12..], size - 12, &request_header.qd_count)
-                    {
-                        let maybe_answers: Result<Vec<DnsMessageResponse>> =
-                            if let Some(resolver_socket) = &maybe_resolver_socket {
-                                resolve_questions(resolver_socket, &request_header, &questions)
-                            } else {
-                                Ok(Vec::with_capacity(0))
-                            };
-
-                        if let Ok(answers) = maybe_answers {
-                            // reponses section
-                            request_header.qr = true;
-                            request_header.qd_count = questions.len() as u16;
-                            request_header.an_count = answers.len() as u16;
-
-                            if request_header.op_code == 0 {
-                                request_header.rcode = 0;
-                            } else {
-                                request_header.rcode = 4;
-                            }
-
-                            let response_header = request_header;
-                            let response_header_bytes: [u8; 12] = (&response_header).into();
-
-                            let response_question_bytes: Vec<u8> =
-                                questions.iter().flat_map(Vec::from).collect();
-
-                            let response_answer_bytes: Vec<u8> =
-                                answers.iter().flat_map(Vec::from).collect();
-
-                            let mut response_bytes = BytesMut::with_capacity(
-                                response_header_bytes.len()
-                                    + response_question_bytes.len()
-                                    + response_answer_bytes.len(),
-                            );
-
-                            response_bytes.put_slice(&response_header_bytes);
-                            response_bytes.put_slice(&response_question_bytes);
-                            response_bytes.put_slice(&response_answer_bytes);
-
-                            if let Err(e) = udp_socket.send_to(&response_bytes.freeze()[..], source)
-                            {
-                                eprintln!("Failed to send response, {}", e);
-                            }
-                        } else {
-                            // respond without answer section
-                            request_header.qr = true;
-                            request_header.qd_count = questions.len() as u16;
-                            request_header.an_count = 0;
-
-                            if request_header.op_code == 0 {
-                                request_header.rcode = 0;
-                            } else {
-                                request_header.rcode = 4;
-                            }
-
-                            let response_header = request_header;
-                            let response_header_bytes: [u8; 12] = (&response_header).into();
-
-                            let response_question_bytes: Vec<u8> =
-                                questions.iter().flat_map(Vec::from).collect();
-
-                            let mut response_bytes = BytesMut::with_capacity(
-                                response_header_bytes.len() + response_question_bytes.len(),
-                            );
-
-                            response_bytes.put_slice(&response_header_bytes);
-                            response_bytes.put_slice(&response_question_bytes);
-
-                            if let Err(e) = udp_socket.send_to(&response_bytes.freeze()[..], source)
-                            {
-                                eprintln!("Failed to send response, {}", e);
-                            }
+                match handle_message(
+                    &buf[..size],
+                    resolver_addr.as_deref(),
+                    tunnel_base.as_deref(),
+                    true,
+                ) {
+                    Ok(response) => {
+                        if let Err(e) = udp_socket.send_to(&response, source) {
+                            eprintln!("Failed to send response, {}", e);
                         }
-                    } else {
-                        eprintln!("Failed to parse questions from {:?}", buf);
                     }
-                } else {
-                    eprintln!("Failed to parse header from {:?}", buf);
+                    Err(e) => eprintln!("Failed to handle message: {}", e),
                 }
             }
             Err(e) => {
@@ -442,19 +1033,169 @@ fn main() {
     }
 }
 
-fn parse_args(args: &[String]) -> Option<&str> {
+fn parse_args(args: &[String]) -> (Option<&str>, Option<&str>) {
     let mut args_iter = args.iter().peekable();
 
     let mut maybe_resolver: Option<&str> = None;
+    let mut maybe_tunnel: Option<&str> = None;
 
     while let Some(arg) = args_iter.next() {
         if arg.starts_with("--resolver") {
             if let Some(next_arg) = args_iter.peek() {
                 maybe_resolver = Some(next_arg);
             }
-            break;
+        } else if arg.starts_with("--tunnel") {
+            if let Some(next_arg) = args_iter.peek() {
+                maybe_tunnel = Some(next_arg);
+            }
+        }
+    }
+
+    (maybe_resolver, maybe_tunnel)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(domain: &str) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for label in domain.split('.') {
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
         }
+        buf.push(0);
+        buf
     }
 
-    maybe_resolver
+    #[test]
+    fn two_questions_share_a_compressed_parent_domain() {
+        let q1 = DnsMessageQuestion {
+            name: name("abc.example.com"),
+            qtype: 1,
+            class: 1,
+        };
+        let q2 = DnsMessageQuestion {
+            name: name("xyz.example.com"),
+            qtype: 1,
+            class: 1,
+        };
+
+        let mut pointers: HashMap<Vec<u8>, u16> = HashMap::new();
+        let mut offset = 12;
+
+        let enc1 = q1.encode(offset, &mut pointers);
+        offset += enc1.len();
+        let enc2 = q2.encode(offset, &mut pointers);
+
+        // The shared "example.com" suffix must collapse to a two-byte pointer,
+        // so the second question is strictly shorter than the first. The pointer
+        // is the tail of the name, which `encode` follows with the 2-byte qtype
+        // and 2-byte class, so its high byte sits six bytes from the end.
+        assert!(enc2.len() < enc1.len());
+        assert_eq!(enc2[enc2.len() - 6] & 0xC0, 0xC0);
+
+        let header = DnsMessageHeader {
+            id: 0x1234,
+            qr: false,
+            op_code: 0,
+            aa: false,
+            tc: false,
+            rd: true,
+            ra: false,
+            z: 0,
+            rcode: 0,
+            qd_count: 2,
+            an_count: 0,
+            ns_count: 0,
+            ar_count: 0,
+        };
+        let header_bytes: [u8; 12] = (&header).into();
+
+        let mut datagram = Vec::new();
+        datagram.extend_from_slice(&header_bytes);
+        datagram.extend_from_slice(&enc1);
+        datagram.extend_from_slice(&enc2);
+
+        let (parsed, _) =
+            dns_questions_from_bytes(&datagram, datagram.len(), 12, &header.qd_count).unwrap();
+
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].name, q1.name);
+        assert_eq!(parsed[1].name, q2.name);
+    }
+
+    #[test]
+    fn self_referential_pointer_is_rejected() {
+        // Header (12 bytes) followed by a pointer at offset 12 that targets
+        // itself. A naive recursive parser loops forever; we must bail out.
+        let mut datagram = vec![0u8; 12];
+        datagram.extend_from_slice(&[0xC0, 0x0C]);
+
+        let result = dns_questions_from_bytes(&datagram, datagram.len(), 12, &1);
+        assert!(matches!(result, Err(Error::InvalidQuestion)));
+    }
+
+    // RFC 4648 base32 alphabet. DNS labels are case-insensitive and must stay
+    // within a safe character set, so base32 (unlike base64) survives the wire
+    // unharmed.
+    const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+    // Padless base32 encoder, the counterpart of [`base32_decode`].
+    fn base32_encode(input: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut buffer = 0u64;
+        let mut bits = 0u32;
+
+        for &b in input {
+            buffer = (buffer << 8) | b as u64;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                out.push(BASE32_ALPHABET[((buffer >> bits) & 0b1_1111) as usize]);
+            }
+        }
+
+        if bits > 0 {
+            out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0b1_1111) as usize]);
+        }
+
+        out
+    }
+
+    // Sender-side counterpart of [`decode_tunnel_question`]: base32-encode a
+    // payload, chunk it into <=63-byte labels prefixed by a sequence label, and
+    // append the tunnel base domain to form a legal QNAME.
+    fn encode_tunnel_query(payload: &[u8], base_domain: &str, seq: u8) -> Vec<u8> {
+        fn push_label(name: &mut Vec<u8>, label: &[u8]) {
+            name.push(label.len() as u8);
+            name.extend_from_slice(label);
+        }
+
+        let mut qname = Vec::new();
+        push_label(&mut qname, &base32_encode(&[seq]));
+        for chunk in base32_encode(payload).chunks(63) {
+            push_label(&mut qname, chunk);
+        }
+        for label in base_domain.split('.') {
+            push_label(&mut qname, label.as_bytes());
+        }
+        qname.push(0);
+
+        qname
+    }
+
+    #[test]
+    fn tunnel_payload_round_trips_through_a_qname() {
+        let payload = b"covert channel \x00\x01\x02\xff payload";
+        let qname = encode_tunnel_query(payload, "tunnel.example.com", 7);
+
+        let (seq, decoded) = decode_tunnel_question(&qname, "tunnel.example.com").unwrap();
+
+        assert_eq!(seq, 7);
+        assert_eq!(decoded, payload);
+
+        // A name outside the tunnel base is left for normal resolution.
+        assert!(decode_tunnel_question(&name("abc.example.com"), "tunnel.example.com").is_none());
+    }
 }